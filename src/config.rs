@@ -0,0 +1,179 @@
+//! `serde`-friendly mirrors of the `perfcnt` event enums, so a counter set
+//! can be described in a TOML/JSON profile and loaded with
+//! `PerfCounters::from_config` instead of being wired up in Rust.
+
+use serde::Deserialize;
+
+use perfcnt::linux::{CacheId, CacheOpId, CacheOpResultId};
+use perfcnt::linux::{HardwareEventType as Hardware, SoftwareEventType as Software};
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum HardwareEvent {
+    CPUCycles,
+    Instructions,
+    CacheReferences,
+    CacheMisses,
+    BranchInstructions,
+    BranchMisses,
+    BusCycles,
+    StalledCyclesFrontend,
+    StalledCyclesBackend,
+    RefCPUCycles,
+}
+
+impl From<HardwareEvent> for Hardware {
+    fn from(event: HardwareEvent) -> Self {
+        match event {
+            HardwareEvent::CPUCycles => Hardware::CPUCycles,
+            HardwareEvent::Instructions => Hardware::Instructions,
+            HardwareEvent::CacheReferences => Hardware::CacheReferences,
+            HardwareEvent::CacheMisses => Hardware::CacheMisses,
+            HardwareEvent::BranchInstructions => Hardware::BranchInstructions,
+            HardwareEvent::BranchMisses => Hardware::BranchMisses,
+            HardwareEvent::BusCycles => Hardware::BusCycles,
+            HardwareEvent::StalledCyclesFrontend => Hardware::StalledCyclesFrontend,
+            HardwareEvent::StalledCyclesBackend => Hardware::StalledCyclesBackend,
+            HardwareEvent::RefCPUCycles => Hardware::RefCPUCycles,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum SoftwareEvent {
+    CpuClock,
+    TaskClock,
+    PageFaults,
+    ContextSwitches,
+    CpuMigrations,
+    PageFaultsMin,
+    PageFaultsMaj,
+    AlignmentFaults,
+    EmulationFaults,
+}
+
+impl From<SoftwareEvent> for Software {
+    fn from(event: SoftwareEvent) -> Self {
+        match event {
+            SoftwareEvent::CpuClock => Software::CpuClock,
+            SoftwareEvent::TaskClock => Software::TaskClock,
+            SoftwareEvent::PageFaults => Software::PageFaults,
+            SoftwareEvent::ContextSwitches => Software::ContextSwitches,
+            SoftwareEvent::CpuMigrations => Software::CpuMigrations,
+            SoftwareEvent::PageFaultsMin => Software::PageFaultsMin,
+            SoftwareEvent::PageFaultsMaj => Software::PageFaultsMaj,
+            SoftwareEvent::AlignmentFaults => Software::AlignmentFaults,
+            SoftwareEvent::EmulationFaults => Software::EmulationFaults,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum CacheIdConfig {
+    L1D,
+    L1I,
+    LL,
+    DTLB,
+    ITLB,
+    BPU,
+    NODE,
+}
+
+impl From<CacheIdConfig> for CacheId {
+    fn from(id: CacheIdConfig) -> Self {
+        match id {
+            CacheIdConfig::L1D => CacheId::L1D,
+            CacheIdConfig::L1I => CacheId::L1I,
+            CacheIdConfig::LL => CacheId::LL,
+            CacheIdConfig::DTLB => CacheId::DTLB,
+            CacheIdConfig::ITLB => CacheId::ITLB,
+            CacheIdConfig::BPU => CacheId::BPU,
+            CacheIdConfig::NODE => CacheId::NODE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum CacheOpIdConfig {
+    Read,
+    Write,
+    Prefetch,
+}
+
+impl From<CacheOpIdConfig> for CacheOpId {
+    fn from(op: CacheOpIdConfig) -> Self {
+        match op {
+            CacheOpIdConfig::Read => CacheOpId::Read,
+            CacheOpIdConfig::Write => CacheOpId::Write,
+            CacheOpIdConfig::Prefetch => CacheOpId::Prefetch,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum CacheOpResultIdConfig {
+    Access,
+    Miss,
+}
+
+impl From<CacheOpResultIdConfig> for CacheOpResultId {
+    fn from(result: CacheOpResultIdConfig) -> Self {
+        match result {
+            CacheOpResultIdConfig::Access => CacheOpResultId::Access,
+            CacheOpResultIdConfig::Miss => CacheOpResultId::Miss,
+        }
+    }
+}
+
+/// One `(CacheId, CacheOpId, CacheOpResultId)` triple, as passed to
+/// `PerfCounters::with_cache_event`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct CacheEventConfig {
+    pub id: CacheIdConfig,
+    pub op: CacheOpIdConfig,
+    pub result: CacheOpResultIdConfig,
+}
+
+/// A named, version-controllable counter profile: which hardware, software
+/// and cache events to arm, and which hardware events (if any) should be
+/// opened as a single PMU group. Load one with `toml::from_str` /
+/// `serde_json::from_str` and hand it to `PerfCounters::from_config`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PerfConfig {
+    #[serde(default)]
+    pub hardware: Vec<HardwareEvent>,
+    #[serde(default)]
+    pub software: Vec<SoftwareEvent>,
+    #[serde(default)]
+    pub cache: Vec<CacheEventConfig>,
+    #[serde(default)]
+    pub grouped_hardware: Vec<HardwareEvent>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserializes_populated_config_from_json() {
+        let json = r#"{
+            "hardware": ["Instructions", "CPUCycles"],
+            "software": ["TaskClock"],
+            "cache": [{"id": "L1D", "op": "Read", "result": "Miss"}],
+            "grouped_hardware": ["BranchMisses"]
+        }"#;
+        let config: PerfConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.hardware.len(), 2);
+        assert_eq!(config.software.len(), 1);
+        assert_eq!(config.cache.len(), 1);
+        assert_eq!(config.grouped_hardware.len(), 1);
+    }
+
+    #[test]
+    fn missing_fields_default_to_empty() {
+        let config: PerfConfig = serde_json::from_str("{}").unwrap();
+        assert!(config.hardware.is_empty());
+        assert!(config.software.is_empty());
+        assert!(config.cache.is_empty());
+        assert!(config.grouped_hardware.is_empty());
+    }
+}
@@ -1,15 +1,136 @@
-use std::{fs::File, io::{self, LineWriter, Write}, path::Path};
+use std::{fs::File, io::{self, LineWriter, Write}, os::unix::io::RawFd, path::Path};
 
-use perfcnt::linux::{CacheId, CacheOpId, CacheOpResultId, PerfCounterBuilderLinux as Builder};
+use perfcnt::linux::{CacheId, CacheOpId, CacheOpResultId};
 use perfcnt::linux::{HardwareEventType as Hardware, SoftwareEventType as Software};
-use perfcnt::{AbstractPerfCounter, PerfCounter};
+
+mod config;
+mod raw;
+mod sampling;
+
+pub use config::{
+    CacheEventConfig, CacheIdConfig, CacheOpIdConfig, CacheOpResultIdConfig, HardwareEvent,
+    PerfConfig, SoftwareEvent,
+};
+pub use sampling::Sampler;
+
+/// Default size of the mmap'd sample ring buffer: `2^DEFAULT_SAMPLE_PAGE_ORDER`
+/// data pages plus the one metadata page the kernel always prepends.
+const DEFAULT_SAMPLE_PAGE_ORDER: u32 = 7;
 
 pub extern crate perfcnt;
 
+/// A counter's raw value plus the scaling info needed to correct for PMU
+/// multiplexing: `scaled()` is the estimated full-window count, `confidence`
+/// is the fraction of the window the event actually ran for (1.0 means it
+/// was never rotated off the PMU).
+#[derive(Clone, Debug)]
+pub struct CounterResult {
+    pub name: String,
+    pub raw: u64,
+    pub time_enabled: u64,
+    pub time_running: u64,
+}
+
+impl CounterResult {
+    fn from_scaled(name: String, count: raw::ScaledCount) -> Self {
+        CounterResult {
+            name,
+            raw: count.raw,
+            time_enabled: count.time_enabled,
+            time_running: count.time_running,
+        }
+    }
+
+    /// The estimated full-window count, or `None` if the event never ran
+    /// (`time_running == 0`, so scaling would divide by zero).
+    pub fn scaled(&self) -> Option<f64> {
+        raw::ScaledCount {
+            raw: self.raw,
+            time_enabled: self.time_enabled,
+            time_running: self.time_running,
+        }
+        .scaled()
+    }
+
+    /// Fraction of the measured window the event actually ran for.
+    pub fn confidence(&self) -> f64 {
+        raw::ScaledCount {
+            raw: self.raw,
+            time_enabled: self.time_enabled,
+            time_running: self.time_running,
+        }
+        .confidence()
+    }
+}
+
+/// Which kind of access to a watched address a breakpoint event should
+/// count. `Execute` cannot be combined with `Read`/`Write` by the kernel.
+#[derive(Debug, Clone, Copy)]
+pub enum BreakpointAccess {
+    Read,
+    Write,
+    ReadWrite,
+    Execute,
+}
+
+impl BreakpointAccess {
+    fn bp_type(self) -> u32 {
+        match self {
+            BreakpointAccess::Read => raw::HW_BREAKPOINT_R,
+            BreakpointAccess::Write => raw::HW_BREAKPOINT_W,
+            BreakpointAccess::ReadWrite => raw::HW_BREAKPOINT_RW,
+            BreakpointAccess::Execute => raw::HW_BREAKPOINT_X,
+        }
+    }
+}
+
 pub struct PerfCounters {
     pid: i32,
-    counters: Vec<(String, PerfCounter)>,
-    results: Vec<(String, u64)>,
+    counters: Vec<(String, RawFd)>,
+    results: Vec<CounterResult>,
+    group: Option<GroupCounters>,
+    sampler: Option<Sampler>,
+    samples: Vec<(u64, u64)>,
+    iterations: Vec<Vec<CounterResult>>,
+}
+
+/// Per-counter min/max/mean/median/stddev across the iterations of a
+/// `bench_iters` run, computed on the scaled (multiplexing-corrected)
+/// values.
+#[derive(Debug, Clone)]
+pub struct IterationStats {
+    pub name: String,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub stddev: f64,
+}
+
+impl Drop for PerfCounters {
+    fn drop(&mut self) {
+        for (_, fd) in &self.counters {
+            raw::close(*fd);
+        }
+    }
+}
+
+/// A group leader fd plus every member fd opened with it as `group_fd`,
+/// in open order (`members[0]` is the leader itself). The kernel schedules
+/// the whole group on/off the PMU together, so `bench` only has to
+/// reset/enable/disable `leader_fd`; a single grouped `read` on it then
+/// returns all members' counts from the same window.
+struct GroupCounters {
+    leader_fd: RawFd,
+    members: Vec<(String, RawFd)>,
+}
+
+impl Drop for GroupCounters {
+    fn drop(&mut self) {
+        for (_, fd) in &self.members {
+            raw::close(*fd);
+        }
+    }
 }
 
 impl PerfCounters {
@@ -18,6 +139,10 @@ impl PerfCounters {
             pid,
             counters: vec![],
             results: vec![],
+            group: None,
+            sampler: None,
+            samples: vec![],
+            iterations: vec![],
         }
     }
     pub fn for_this_process() -> Self {
@@ -25,21 +150,35 @@ impl PerfCounters {
         println!("Current pid is: {}", pid);
         Self::for_pid(pid)
     }
+    /// Builds a counter set from a `PerfConfig` loaded from a profile file,
+    /// so which events `bench` measures can be changed without recompiling.
+    pub fn from_config(pid: i32, config: PerfConfig) -> Self {
+        let mut counters = Self::for_pid(pid);
+        if !config.hardware.is_empty() {
+            counters.with_hardware_events(config.hardware.into_iter().map(Into::into).collect());
+        }
+        if !config.software.is_empty() {
+            counters.with_software_events(config.software.into_iter().map(Into::into).collect());
+        }
+        for cache in config.cache {
+            counters.with_cache_event(cache.id.into(), cache.op.into(), cache.result.into());
+        }
+        if !config.grouped_hardware.is_empty() {
+            counters.with_grouped_hardware_events(
+                config.grouped_hardware.into_iter().map(Into::into).collect(),
+            );
+        }
+        counters
+    }
     pub fn with_software_events(&mut self, events: Vec<Software>) -> &mut Self {
         self.counters.append(
             &mut events
                 .into_iter()
                 .filter_map(|event| {
                     let name = format!("{:?}", event);
-                    match Builder::from_software_event(event)
-                        .for_pid(self.pid)
-                        .inherit()
-                        .on_all_cpus()
-                        .exclude_kernel()
-                        .exclude_idle()
-                        .finish()
-                    {
-                        Ok(pc) => Some((name, pc)),
+                    let attr = raw::PerfEventAttr::for_software_event(event);
+                    match raw::perf_event_open(&attr, self.pid, -1) {
+                        Ok(fd) => Some((name, fd)),
                         Err(e) => {
                             println!("Could not create {}, reason '{:?}'", name, e);
                             None
@@ -56,15 +195,9 @@ impl PerfCounters {
                 .into_iter()
                 .filter_map(|event| {
                     let name = format!("{:?}", event);
-                    match Builder::from_hardware_event(event)
-                        .for_pid(self.pid)
-                        .inherit()
-                        .on_all_cpus()
-                        .exclude_kernel()
-                        .exclude_idle()
-                        .finish()
-                    {
-                        Ok(pc) => Some((name, pc)),
+                    let attr = raw::PerfEventAttr::for_hardware_event(event);
+                    match raw::perf_event_open(&attr, self.pid, -1) {
+                        Ok(fd) => Some((name, fd)),
                         Err(e) => {
                             println!("Could not create {}, reason '{:?}'", name, e);
                             None
@@ -75,6 +208,105 @@ impl PerfCounters {
         );
         self
     }
+    /// Like `with_hardware_events`, but opens every event as a single PMU
+    /// group with the first one as leader, so their counts come from the
+    /// exact same on/off window instead of being multiplexed independently.
+    /// Group membership accumulates across calls: the very first grouped
+    /// event this `PerfCounters` ever opens becomes the leader for all
+    /// later ones. Nothing stops this from being called again after `bench`
+    /// has already run once; the new member is simply opened against the
+    /// existing leader fd and joins the group from its next `bench` call on.
+    pub fn with_grouped_hardware_events(&mut self, events: Vec<Hardware>) -> &mut Self {
+        for event in events {
+            let name = format!("{:?}", event);
+            let mut attr = raw::PerfEventAttr::for_hardware_event(event);
+            attr.read_format |= raw::PERF_FORMAT_GROUP;
+            let group_fd = self.group.as_ref().map(|g| g.leader_fd).unwrap_or(-1);
+            match raw::perf_event_open(&attr, self.pid, group_fd) {
+                Ok(fd) => match &mut self.group {
+                    Some(g) => g.members.push((name, fd)),
+                    None => {
+                        self.group = Some(GroupCounters {
+                            leader_fd: fd,
+                            members: vec![(name, fd)],
+                        })
+                    }
+                },
+                Err(e) => println!("Could not create grouped {}, reason '{:?}'", name, e),
+            }
+        }
+        self
+    }
+    /// Arms `event` for sampling instead of counting: every `sample_period`
+    /// occurrences the kernel records the interrupted instruction pointer
+    /// into a ring buffer, which `bench` drains into an IP histogram
+    /// readable via `samples()` afterwards. Replaces any sampler set by an
+    /// earlier call.
+    pub fn with_sampling(&mut self, event: Hardware, sample_period: u64) -> &mut Self {
+        match Sampler::for_hardware_event(event, self.pid, sample_period, DEFAULT_SAMPLE_PAGE_ORDER) {
+            Ok(sampler) => self.sampler = Some(sampler),
+            Err(e) => println!("Could not arm sampler for {:?}, reason '{:?}'", event, e),
+        }
+        self
+    }
+    /// `(ip, count)` histogram collected by `with_sampling`, most-sampled
+    /// instruction pointer first. Empty if no sampler was armed.
+    pub fn samples(&self) -> &[(u64, u64)] {
+        &self.samples
+    }
+    /// Counts accesses to a memory address or address range, mirroring the
+    /// kernel's `mem:addr/len:type` breakpoint events — handy for pinning
+    /// down which hot variable or cache line is being hammered. If the
+    /// platform rejects `len` as a hardware range-breakpoint length (only
+    /// some CPUs support those) and `len` isn't already a standard width,
+    /// falls back to the largest standard exact-width watchpoint (8/4/2/1
+    /// bytes) that fits within `len` and reports that the requested range
+    /// wasn't honored. If `len` was already a standard width, the rejection
+    /// wasn't about the range, so there's no different fallback config to
+    /// retry with and the original error is reported as-is.
+    pub fn with_breakpoint(&mut self, addr: u64, len: u64, access: BreakpointAccess) -> &mut Self {
+        let name = format!("breakpoint_{:#x}_{}_{:?}", addr, len, access);
+        let bp_type = access.bp_type();
+        let attr = raw::PerfEventAttr::for_breakpoint(bp_type, addr, len);
+        match raw::perf_event_open(&attr, self.pid, -1) {
+            Ok(fd) => self.counters.push((name, fd)),
+            Err(e) => match fallback_breakpoint_width(len) {
+                None => {
+                    println!("No exact-width watchpoint fits a {}-byte range", len)
+                }
+                // `len` was already a standard exact width, so retrying
+                // with the same length would just reopen the identical
+                // config and fail the same way (e.g. EACCES/paranoid) —
+                // the rejection wasn't about the range, there's nothing
+                // different to fall back to.
+                Some(fallback_len) if fallback_len == len => println!(
+                    "Could not open {}-byte watchpoint at {:#x}, reason '{:?}'",
+                    len, addr, e
+                ),
+                Some(fallback_len) => {
+                    println!(
+                        "Could not open {}-byte range breakpoint at {:#x}, reason '{:?}'; falling back to an exact-width watchpoint",
+                        len, addr, e
+                    );
+                    let attr = raw::PerfEventAttr::for_breakpoint(bp_type, addr, fallback_len);
+                    match raw::perf_event_open(&attr, self.pid, -1) {
+                        Ok(fd) => {
+                            println!(
+                                "Opened a {}-byte watchpoint at {:#x} instead of the requested {}-byte range",
+                                fallback_len, addr, len
+                            );
+                            self.counters.push((name, fd));
+                        }
+                        Err(e) => println!(
+                            "Could not open fallback {}-byte watchpoint at {:#x}, reason '{:?}'",
+                            fallback_len, addr, e
+                        ),
+                    }
+                }
+            },
+        }
+        self
+    }
     pub fn with_cache_event(
         &mut self,
         cache_id: CacheId,
@@ -82,16 +314,10 @@ impl PerfCounters {
         cache_op_result_id: CacheOpResultId,
     ) -> &mut Self {
         let name = format!("{:?}_{:?}_{:?}", cache_id, cache_op_id, cache_op_result_id);
-        match Builder::from_cache_event(cache_id, cache_op_id, cache_op_result_id)
-            .for_pid(self.pid)
-            .inherit()
-            .on_all_cpus()
-            .exclude_kernel()
-            .exclude_idle()
-            .finish()
-        {
-            Ok(pc) => {
-                self.counters.push((name, pc));
+        let attr = raw::PerfEventAttr::for_cache_event(cache_id, cache_op_id, cache_op_result_id);
+        match raw::perf_event_open(&attr, self.pid, -1) {
+            Ok(fd) => {
+                self.counters.push((name, fd));
             }
             Err(e) => {
                 println!("Could not create {}, reason '{:?}'", name, e);
@@ -123,25 +349,47 @@ impl PerfCounters {
     pub fn with_all_branch_prediction_events(&mut self) -> &mut Self {
         self.with_all_cache_events_for(&[CacheId::BPU])
     }
+    /// Resets and re-runs the counters over `func`, replacing whatever
+    /// `results`/`iterations` an earlier `bench` or `bench_iters` call left
+    /// behind so a later `save_result` reflects this run only.
     pub fn bench<F, R>(&mut self, func: F) -> R
     where
         F: FnOnce() -> R,
     {
-        for (c, pc) in &mut self.counters {
-            let _ = pc.reset();
-            if let Err(e) = pc.start() {
+        self.results.clear();
+        self.iterations.clear();
+        for (c, fd) in &self.counters {
+            let _ = raw::reset(*fd);
+            if let Err(e) = raw::enable(*fd) {
                 println!("Cannot start {}, reason: {}", c, e);
             }
         }
+        if let Some(group) = &self.group {
+            let _ = raw::reset(group.leader_fd);
+            if let Err(e) = raw::enable(group.leader_fd) {
+                println!("Cannot start counter group, reason: {}", e);
+            }
+        }
+        if let Some(sampler) = &self.sampler {
+            if let Err(e) = sampler.start() {
+                println!("Cannot start sampler, reason: {}", e);
+            }
+        }
         let res = func();
-        for (c, pc) in &mut self.counters {
-            if let Err(e) = pc.stop() {
+        for (c, fd) in &self.counters {
+            if let Err(e) = raw::disable(*fd) {
                 println!("Cannot stop {}, reason: {}", c, e);
             } else {
-                match pc.read() {
-                    Ok(num) => {
-                        self.results.push((c.to_owned(), num));
-                        println!("{}\t{}", c, num)
+                match raw::read_scaled(*fd) {
+                    Ok(count) => {
+                        println!(
+                            "{}\t{:.0} (raw {}, confidence {:.2})",
+                            c,
+                            count.scaled().unwrap_or(f64::NAN),
+                            count.raw,
+                            count.confidence()
+                        );
+                        self.results.push(CounterResult::from_scaled(c.to_owned(), count));
                     }
                     Err(e) => {
                         println!("Cannot read {}, reason: {}", c, e)
@@ -149,35 +397,223 @@ impl PerfCounters {
                 }
             }
         }
+        if let Some(group) = &self.group {
+            if let Err(e) = raw::disable(group.leader_fd) {
+                println!("Cannot stop counter group, reason: {}", e);
+            } else {
+                match raw::read_group(group.leader_fd, group.members.len()) {
+                    Ok((values, time_enabled, time_running)) => {
+                        for ((name, _), value) in group.members.iter().zip(values) {
+                            let count = raw::ScaledCount {
+                                raw: value,
+                                time_enabled,
+                                time_running,
+                            };
+                            println!(
+                                "{}\t{:.0} (raw {}, confidence {:.2})",
+                                name,
+                                count.scaled().unwrap_or(f64::NAN),
+                                count.raw,
+                                count.confidence()
+                            );
+                            self.results.push(CounterResult::from_scaled(name.clone(), count));
+                        }
+                    }
+                    Err(e) => println!("Cannot read counter group, reason: {}", e),
+                }
+            }
+        }
+        if let Some(sampler) = &mut self.sampler {
+            if let Err(e) = sampler.stop() {
+                println!("Cannot stop sampler, reason: {}", e);
+            } else {
+                self.samples = sampler.drain_ip_histogram();
+            }
+        }
         res
     }
+    /// Ratios derived from well-known counter-name pairs already present in
+    /// `results`: IPC/CPI from `Instructions`/`CPUCycles`, a miss rate per
+    /// cache id seen (which also covers branch misprediction rate via the
+    /// `BPU` cache id), and stalled-cycle fractions when those events were
+    /// collected. Missing or zero denominators are skipped rather than
+    /// producing an `inf`/`NaN` entry.
+    pub fn derived_metrics(&self) -> Vec<(String, f64)> {
+        derived_metrics_for(&self.results)
+    }
+    /// Runs the measured region `n` times, resetting and re-reading the
+    /// counters each time without re-opening any fds (`bench` already does
+    /// that per call), and keeps each iteration's readings separately so
+    /// `iteration_stats` can quantify run-to-run noise. Returns the last
+    /// iteration's result.
+    pub fn bench_iters<F, R>(&mut self, n: usize, mut func: F) -> R
+    where
+        F: FnMut() -> R,
+    {
+        assert!(n > 0, "bench_iters requires at least one iteration");
+        let mut iterations = Vec::with_capacity(n);
+        let mut last = None;
+        for _ in 0..n {
+            let result = self.bench(&mut func);
+            iterations.push(self.results.clone());
+            last = Some(result);
+        }
+        self.iterations = iterations;
+        last.unwrap()
+    }
+    /// Per-counter min/max/mean/median/stddev across the iterations
+    /// collected by the last `bench_iters` call, on scaled values (falling
+    /// back to the raw count if an iteration's event never ran).
+    pub fn iteration_stats(&self) -> Vec<IterationStats> {
+        let mut by_name: Vec<(String, Vec<f64>)> = Vec::new();
+        for iteration in &self.iterations {
+            for r in iteration {
+                let value = r.scaled().unwrap_or(r.raw as f64);
+                match by_name.iter_mut().find(|(name, _)| name == &r.name) {
+                    Some((_, values)) => values.push(value),
+                    None => by_name.push((r.name.clone(), vec![value])),
+                }
+            }
+        }
+        by_name
+            .into_iter()
+            .map(|(name, mut values)| {
+                values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                let count = values.len() as f64;
+                let mean = values.iter().sum::<f64>() / count;
+                let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / count;
+                let median = if values.len() % 2 == 0 {
+                    (values[values.len() / 2 - 1] + values[values.len() / 2]) / 2.0
+                } else {
+                    values[values.len() / 2]
+                };
+                IterationStats {
+                    name,
+                    min: values[0],
+                    max: values[values.len() - 1],
+                    mean,
+                    median,
+                    stddev: variance.sqrt(),
+                }
+            })
+            .collect()
+    }
+    /// Writes a single-row CSV for a plain `bench` run (raw/scaled/
+    /// confidence per counter plus `derived_metrics`), or, after
+    /// `bench_iters`, one row per iteration (raw/scaled/confidence plus that
+    /// iteration's own derived metrics) followed by a min/max/mean/median/
+    /// stddev summary block so run-to-run noise is visible too.
     pub fn save_result<P: AsRef<Path>>(&mut self, path: P) -> io::Result<&mut Self> {
-        if self.results.is_empty() {
+        if self.results.is_empty() && self.iterations.is_empty() {
             println!("No results to sav");
+            return Ok(self);
+        }
+        let file = File::create(path)?;
+        let mut file = LineWriter::new(file);
+        if self.iterations.is_empty() {
+            let derived = self.derived_metrics();
+            let head_line = counter_columns(&self.results)
+                .into_iter()
+                .chain(derived.iter().map(|(name, _)| name.clone()))
+                .collect::<Vec<_>>()
+                .join(",");
+            let result_line = counter_values(&self.results)
+                .into_iter()
+                .chain(derived.iter().map(|(_, value)| format!("{}", value)))
+                .collect::<Vec<_>>()
+                .join(",");
+            file.write_all(head_line.as_bytes())?;
+            file.write_all(b"\n")?;
+            file.write_all(result_line.as_bytes())?;
+            file.write_all(b"\n")?;
         } else {
-            let file = File::create(path)?;
-            let mut file = LineWriter::new(file);
-            let head_line = self
-                .results
-                .iter()
-                .map(|(s, _)| s.to_string())
+            let derived_names: Vec<String> = derived_metrics_for(&self.iterations[0])
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect();
+            let head_line = std::iter::once("iteration".to_string())
+                .chain(counter_columns(&self.iterations[0]))
+                .chain(derived_names)
                 .collect::<Vec<_>>()
                 .join(",");
-            let result_line = self
-                .results
-                .iter()
-                .map(|(_, n)| format!("{}", n))
+            file.write_all(head_line.as_bytes())?;
+            file.write_all(b"\n")?;
+            for (i, iteration) in self.iterations.iter().enumerate() {
+                let derived = derived_metrics_for(iteration);
+                let row = std::iter::once(format!("{}", i))
+                    .chain(counter_values(iteration))
+                    .chain(derived.into_iter().map(|(_, value)| format!("{}", value)))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                file.write_all(row.as_bytes())?;
+                file.write_all(b"\n")?;
+            }
+            file.write_all(b"\n")?;
+            let stats = self.iteration_stats();
+            let stat_head = std::iter::once("stat".to_string())
+                .chain(stats.iter().map(|s| s.name.clone()))
                 .collect::<Vec<_>>()
                 .join(",");
-            file.write(head_line.as_bytes())?;
-            file.write(b"\n")?;
-            file.write(result_line.as_bytes())?;
-            file.flush()?;
+            file.write_all(stat_head.as_bytes())?;
+            file.write_all(b"\n")?;
+            let stat_rows: [(&str, StatPicker); 5] = [
+                ("min", |s| s.min),
+                ("max", |s| s.max),
+                ("mean", |s| s.mean),
+                ("median", |s| s.median),
+                ("stddev", |s| s.stddev),
+            ];
+            for (label, pick) in stat_rows {
+                let row = std::iter::once(label.to_string())
+                    .chain(stats.iter().map(|s| format!("{}", pick(s))))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                file.write_all(row.as_bytes())?;
+                file.write_all(b"\n")?;
+            }
         }
-        return Ok(self);
+        file.flush()?;
+        Ok(self)
     }
 }
 
+/// A field accessor used to print one row of the iteration-stats summary table.
+type StatPicker = fn(&IterationStats) -> f64;
+
+/// `"<name>,<name>_scaled,<name>_confidence"` per counter, in order.
+fn counter_columns(results: &[CounterResult]) -> Vec<String> {
+    results
+        .iter()
+        .flat_map(|r| {
+            vec![
+                r.name.clone(),
+                format!("{}_scaled", r.name),
+                format!("{}_confidence", r.name),
+            ]
+        })
+        .collect()
+}
+
+/// `"<raw>,<scaled or NaN>,<confidence>"` per counter, matching `counter_columns`.
+fn counter_values(results: &[CounterResult]) -> Vec<String> {
+    results
+        .iter()
+        .flat_map(|r| {
+            vec![
+                format!("{}", r.raw),
+                r.scaled().map(|v| format!("{}", v)).unwrap_or("NaN".to_string()),
+                format!("{}", r.confidence()),
+            ]
+        })
+        .collect()
+}
+
+/// The largest standard hardware watchpoint width (8/4/2/1 bytes) that
+/// fits within `len`, or `None` if `len` is zero.
+fn fallback_breakpoint_width(len: u64) -> Option<u64> {
+    [8u64, 4, 2, 1].into_iter().find(|&width| width <= len)
+}
+
 fn all_cache_ops() -> [CacheOpId; 3] {
     [CacheOpId::Read, CacheOpId::Write, CacheOpId::Prefetch]
 }
@@ -186,11 +622,89 @@ fn all_cache_res() -> [CacheOpResultId; 2] {
     [CacheOpResultId::Access, CacheOpResultId::Miss]
 }
 
+/// Ratios derived from well-known counter-name pairs in `results`: IPC/CPI
+/// from `Instructions`/`CPUCycles`, a miss rate per cache id seen (which
+/// also covers branch misprediction rate via the `BPU` cache id), and
+/// stalled-cycle fractions when those events were collected. Missing or
+/// zero denominators are skipped rather than producing an `inf`/`NaN`
+/// entry. Shared by `derived_metrics` (on the latest `bench` run) and
+/// `save_result`'s per-iteration columns after `bench_iters`.
+fn derived_metrics_for(results: &[CounterResult]) -> Vec<(String, f64)> {
+    let mut metrics = Vec::new();
+    let lookup = |name: &str| -> Option<f64> {
+        results.iter().find(|r| r.name == name).and_then(|r| r.scaled())
+    };
+    if let (Some(instructions), Some(cycles)) = (lookup("Instructions"), lookup("CPUCycles")) {
+        if cycles != 0.0 {
+            metrics.push(("IPC".to_string(), instructions / cycles));
+        }
+        if instructions != 0.0 {
+            metrics.push(("CPI".to_string(), cycles / instructions));
+        }
+    }
+    if let Some(cycles) = lookup("CPUCycles") {
+        if cycles != 0.0 {
+            if let Some(frontend) = lookup("StalledCyclesFrontend") {
+                metrics.push(("StalledCyclesFrontendFraction".to_string(), frontend / cycles));
+            }
+            if let Some(backend) = lookup("StalledCyclesBackend") {
+                metrics.push(("StalledCyclesBackendFraction".to_string(), backend / cycles));
+            }
+        }
+    }
+    for id in cache_ids_seen(results) {
+        let access = sum_cache_result(results, &id, "Access");
+        let miss = sum_cache_result(results, &id, "Miss");
+        if access != 0.0 {
+            let label = if id == "BPU" {
+                "BranchMispredictionRate".to_string()
+            } else {
+                format!("{}MissRate", id)
+            };
+            metrics.push((label, miss / access));
+        }
+    }
+    metrics
+}
+
+/// Cache events are named `"{CacheId}_{CacheOpId}_{CacheOpResultId}"` by
+/// `with_cache_event`; this pulls out the distinct `CacheId` part of every
+/// such name present in `results`.
+fn cache_ids_seen(results: &[CounterResult]) -> Vec<String> {
+    let mut ids: Vec<String> = results
+        .iter()
+        .filter_map(|r| {
+            let parts: Vec<&str> = r.name.splitn(3, '_').collect();
+            if parts.len() == 3 {
+                Some(parts[0].to_string())
+            } else {
+                None
+            }
+        })
+        .collect();
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+/// Sums the scaled counts of every op (`Read`/`Write`/`Prefetch`) collected
+/// for `cache_id` with the given result kind (`"Access"` or `"Miss"`).
+fn sum_cache_result(results: &[CounterResult], cache_id: &str, result_kind: &str) -> f64 {
+    results
+        .iter()
+        .filter(|r| {
+            let parts: Vec<&str> = r.name.splitn(3, '_').collect();
+            parts.len() == 3 && parts[0] == cache_id && parts[2] == result_kind
+        })
+        .filter_map(|r| r.scaled())
+        .sum()
+}
+
 #[cfg(test)]
 mod tests {
     use perfcnt::linux::{HardwareEventType, SoftwareEventType};
 
-    use crate::PerfCounters;
+    use crate::{fallback_breakpoint_width, CounterResult, PerfCounters};
 
     #[test]
     fn it_works() {
@@ -211,4 +725,128 @@ mod tests {
                 println!("{}", a);
             });
     }
+
+    /// Builds a `PerfCounters` with no real fds, just pre-seeded `results`/
+    /// `iterations`, so the pure post-processing logic (derived metrics,
+    /// iteration stats) can be tested without PMU hardware.
+    fn counters_with_results(results: Vec<CounterResult>) -> PerfCounters {
+        PerfCounters {
+            pid: 0,
+            counters: vec![],
+            results,
+            group: None,
+            sampler: None,
+            samples: vec![],
+            iterations: vec![],
+        }
+    }
+
+    fn count(name: &str, raw: u64) -> CounterResult {
+        CounterResult {
+            name: name.to_string(),
+            raw,
+            time_enabled: 100,
+            time_running: 100,
+        }
+    }
+
+    #[test]
+    fn bench_clears_stale_results_and_iterations_from_earlier_runs() {
+        let mut counters = counters_with_results(vec![count("Instructions", 2000)]);
+        counters.iterations = vec![vec![count("Instructions", 10)], vec![count("Instructions", 20)]];
+        counters.bench(|| ());
+        assert!(
+            counters.results.is_empty(),
+            "bench should drop the previous run's results before starting a fresh one"
+        );
+        assert!(
+            counters.iterations.is_empty(),
+            "bench should drop a prior bench_iters run so save_result doesn't serve stale data"
+        );
+    }
+
+    #[test]
+    fn fallback_breakpoint_width_picks_largest_standard_width_that_fits() {
+        assert_eq!(fallback_breakpoint_width(8), Some(8));
+        assert_eq!(fallback_breakpoint_width(5), Some(4));
+        assert_eq!(fallback_breakpoint_width(3), Some(2));
+        assert_eq!(fallback_breakpoint_width(1), Some(1));
+        assert_eq!(fallback_breakpoint_width(0), None);
+    }
+
+    #[test]
+    fn cache_ids_seen_and_sum_cache_result() {
+        let results = vec![
+            count("L1D_Read_Access", 100),
+            count("L1D_Write_Access", 50),
+            count("L1D_Read_Miss", 5),
+            count("BPU_Read_Access", 200),
+        ];
+        assert_eq!(
+            super::cache_ids_seen(&results),
+            vec!["BPU".to_string(), "L1D".to_string()]
+        );
+        assert_eq!(super::sum_cache_result(&results, "L1D", "Access"), 150.0);
+        assert_eq!(super::sum_cache_result(&results, "L1D", "Miss"), 5.0);
+        assert_eq!(super::sum_cache_result(&results, "L1D", "Prefetch"), 0.0);
+    }
+
+    #[test]
+    fn derived_metrics_computes_ipc_and_cache_miss_rate() {
+        let counters = counters_with_results(vec![
+            count("Instructions", 2000),
+            count("CPUCycles", 1000),
+            count("L1D_Read_Access", 100),
+            count("L1D_Read_Miss", 10),
+        ]);
+        let metrics: std::collections::HashMap<_, _> = counters.derived_metrics().into_iter().collect();
+        assert_eq!(metrics["IPC"], 2.0);
+        assert_eq!(metrics["CPI"], 0.5);
+        assert_eq!(metrics["L1DMissRate"], 0.1);
+    }
+
+    #[test]
+    fn derived_metrics_skips_zero_denominator() {
+        let counters = counters_with_results(vec![count("Instructions", 100), count("CPUCycles", 0)]);
+        let metrics: std::collections::HashMap<_, _> = counters.derived_metrics().into_iter().collect();
+        assert!(!metrics.contains_key("IPC"), "IPC divides by zero cycles and should be skipped");
+        assert_eq!(metrics["CPI"], 0.0);
+    }
+
+    #[test]
+    fn derived_metrics_skips_missing_counters() {
+        let counters = counters_with_results(vec![count("Instructions", 100)]);
+        assert!(counters.derived_metrics().is_empty());
+    }
+
+    #[test]
+    fn iteration_stats_computes_min_max_mean_and_stddev() {
+        let mut counters = counters_with_results(vec![]);
+        counters.iterations = vec![
+            vec![count("Instructions", 10)],
+            vec![count("Instructions", 20)],
+            vec![count("Instructions", 30)],
+            vec![count("Instructions", 40)],
+        ];
+        let stats = counters.iteration_stats();
+        let s = stats.iter().find(|s| s.name == "Instructions").unwrap();
+        assert_eq!(s.min, 10.0);
+        assert_eq!(s.max, 40.0);
+        assert_eq!(s.mean, 25.0);
+        assert_eq!(s.median, 25.0);
+        assert!((s.stddev - 11.180339887498949).abs() < 1e-9);
+    }
+
+    #[test]
+    fn iteration_stats_median_with_odd_count() {
+        let mut counters = counters_with_results(vec![]);
+        counters.iterations = vec![
+            vec![count("X", 1)],
+            vec![count("X", 5)],
+            vec![count("X", 3)],
+        ];
+        let stats = counters.iteration_stats();
+        let s = stats.iter().find(|s| s.name == "X").unwrap();
+        assert_eq!(s.median, 3.0);
+    }
 }
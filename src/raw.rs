@@ -0,0 +1,389 @@
+//! Thin bindings to `perf_event_open(2)`, used for every counter this crate
+//! opens rather than just the features `perfcnt::linux::PerfCounterBuilderLinux`
+//! lacks. `perfcnt` is still a dependency — `Hardware`/`Software`/`CacheId`/
+//! `CacheOpId`/`CacheOpResultId` are its enums, reused everywhere for the
+//! event vocabulary — but its `PerfCounter` wrapper owns one private fd per
+//! counter with no way to pass another counter's fd in as `group_fd`, no
+//! `PERF_FORMAT_GROUP` read support, and no path to breakpoint or sampling
+//! events (the latter marked NYI upstream, and its own `SamplingPerfCounter`
+//! has known bugs). Group leaders, breakpoints and the sampling ring buffer
+//! all need that lower-level control, so rather than keep two competing
+//! perf-event abstractions in this crate, every counter — including the
+//! plain ones chunk0-2 needed time_enabled/time_running scaling for — goes
+//! through this module's own `perf_event_open`/ioctl/`perf_event_attr`
+//! plumbing, which mirrors the kernel uapi in `linux/perf_event.h` closely
+//! enough to build a correct `perf_event_attr` and parse the buffers
+//! `read(2)` hands back.
+
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use perfcnt::linux::{CacheId, CacheOpId, CacheOpResultId};
+use perfcnt::linux::{HardwareEventType as Hardware, SoftwareEventType as Software};
+
+pub const PERF_TYPE_HARDWARE: u32 = 0;
+pub const PERF_TYPE_SOFTWARE: u32 = 1;
+pub const PERF_TYPE_HW_CACHE: u32 = 3;
+pub const PERF_TYPE_BREAKPOINT: u32 = 5;
+
+pub const HW_BREAKPOINT_R: u32 = 1;
+pub const HW_BREAKPOINT_W: u32 = 2;
+pub const HW_BREAKPOINT_RW: u32 = HW_BREAKPOINT_R | HW_BREAKPOINT_W;
+pub const HW_BREAKPOINT_X: u32 = 4;
+
+pub const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+pub const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+pub const PERF_FORMAT_GROUP: u64 = 1 << 3;
+
+/// The read format used for every counter this crate opens: alongside the
+/// raw count the kernel hands back how long the event was enabled vs.
+/// actually scheduled on the PMU, which is what lets `bench` correct for
+/// multiplexing instead of reporting whatever fraction of the window the
+/// event happened to get.
+pub const DEFAULT_READ_FORMAT: u64 = PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING;
+
+const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+const PERF_COUNT_HW_CACHE_REFERENCES: u64 = 2;
+const PERF_COUNT_HW_CACHE_MISSES: u64 = 3;
+const PERF_COUNT_HW_BRANCH_INSTRUCTIONS: u64 = 4;
+const PERF_COUNT_HW_BRANCH_MISSES: u64 = 5;
+const PERF_COUNT_HW_BUS_CYCLES: u64 = 6;
+const PERF_COUNT_HW_STALLED_CYCLES_FRONTEND: u64 = 7;
+const PERF_COUNT_HW_STALLED_CYCLES_BACKEND: u64 = 8;
+const PERF_COUNT_HW_REF_CPU_CYCLES: u64 = 9;
+
+const PERF_COUNT_SW_CPU_CLOCK: u64 = 0;
+const PERF_COUNT_SW_TASK_CLOCK: u64 = 1;
+const PERF_COUNT_SW_PAGE_FAULTS: u64 = 2;
+const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+const PERF_COUNT_SW_CPU_MIGRATIONS: u64 = 4;
+const PERF_COUNT_SW_PAGE_FAULTS_MIN: u64 = 5;
+const PERF_COUNT_SW_PAGE_FAULTS_MAJ: u64 = 6;
+const PERF_COUNT_SW_ALIGNMENT_FAULTS: u64 = 7;
+const PERF_COUNT_SW_EMULATION_FAULTS: u64 = 8;
+
+/// `struct perf_event_attr` from `linux/perf_event.h`, trimmed to the fields
+/// this crate actually sets. Anything left implicit is zeroed (no sampling);
+/// `new` sets the `disabled` flag so a counter stays off until enabled.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PerfEventAttr {
+    pub type_: u32,
+    pub size: u32,
+    pub config: u64,
+    pub sample_period_or_freq: u64,
+    pub sample_type: u64,
+    pub read_format: u64,
+    pub flags: u64,
+    pub wakeup_events_or_watermark: u32,
+    pub bp_type: u32,
+    pub bp_addr_or_config1: u64,
+    pub bp_len_or_config2: u64,
+    pub branch_sample_type: u64,
+    pub sample_regs_user: u64,
+    pub sample_stack_user: u32,
+    pub clockid: i32,
+    pub sample_regs_intr: u64,
+    pub aux_watermark: u32,
+    pub sample_max_stack: u16,
+    pub __reserved_2: u16,
+}
+
+const FLAG_DISABLED: u64 = 1 << 0;
+const FLAG_INHERIT: u64 = 1 << 1;
+const FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+const FLAG_EXCLUDE_HV: u64 = 1 << 6;
+const FLAG_EXCLUDE_IDLE: u64 = 1 << 7;
+
+impl PerfEventAttr {
+    pub fn new(type_: u32, config: u64) -> Self {
+        PerfEventAttr {
+            type_,
+            size: mem::size_of::<PerfEventAttr>() as u32,
+            config,
+            sample_period_or_freq: 0,
+            sample_type: 0,
+            read_format: DEFAULT_READ_FORMAT,
+            flags: FLAG_DISABLED | FLAG_INHERIT | FLAG_EXCLUDE_KERNEL | FLAG_EXCLUDE_HV | FLAG_EXCLUDE_IDLE,
+            wakeup_events_or_watermark: 0,
+            bp_type: 0,
+            bp_addr_or_config1: 0,
+            bp_len_or_config2: 0,
+            branch_sample_type: 0,
+            sample_regs_user: 0,
+            sample_stack_user: 0,
+            clockid: 0,
+            sample_regs_intr: 0,
+            aux_watermark: 0,
+            sample_max_stack: 0,
+            __reserved_2: 0,
+        }
+    }
+
+    pub fn for_hardware_event(event: Hardware) -> Self {
+        PerfEventAttr::new(PERF_TYPE_HARDWARE, hardware_config(event))
+    }
+
+    pub fn for_software_event(event: Software) -> Self {
+        PerfEventAttr::new(PERF_TYPE_SOFTWARE, software_config(event))
+    }
+
+    pub fn for_cache_event(id: CacheId, op: CacheOpId, result: CacheOpResultId) -> Self {
+        PerfEventAttr::new(PERF_TYPE_HW_CACHE, cache_config(id, op, result))
+    }
+
+    /// A `PERF_TYPE_BREAKPOINT` event watching `bp_len` bytes at `bp_addr`
+    /// for the accesses named by `bp_type` (`HW_BREAKPOINT_*`). `config` is
+    /// unused for this event type and must be left zero.
+    pub fn for_breakpoint(bp_type: u32, bp_addr: u64, bp_len: u64) -> Self {
+        let mut attr = PerfEventAttr::new(PERF_TYPE_BREAKPOINT, 0);
+        attr.bp_type = bp_type;
+        attr.bp_addr_or_config1 = bp_addr;
+        attr.bp_len_or_config2 = bp_len;
+        attr
+    }
+}
+
+/// `perf_hw_cache_id` / `_op_id` / `_op_result_id`, packed the way the
+/// kernel expects in `config`: `id | (op << 8) | (result << 16)`.
+fn cache_config(id: CacheId, op: CacheOpId, result: CacheOpResultId) -> u64 {
+    let id = match id {
+        CacheId::L1D => 0,
+        CacheId::L1I => 1,
+        CacheId::LL => 2,
+        CacheId::DTLB => 3,
+        CacheId::ITLB => 4,
+        CacheId::BPU => 5,
+        CacheId::NODE => 6,
+    };
+    let op = match op {
+        CacheOpId::Read => 0,
+        CacheOpId::Write => 1,
+        CacheOpId::Prefetch => 2,
+    };
+    let result = match result {
+        CacheOpResultId::Access => 0,
+        CacheOpResultId::Miss => 1,
+    };
+    id | (op << 8) | (result << 16)
+}
+
+fn hardware_config(event: Hardware) -> u64 {
+    match event {
+        Hardware::CPUCycles => PERF_COUNT_HW_CPU_CYCLES,
+        Hardware::Instructions => PERF_COUNT_HW_INSTRUCTIONS,
+        Hardware::CacheReferences => PERF_COUNT_HW_CACHE_REFERENCES,
+        Hardware::CacheMisses => PERF_COUNT_HW_CACHE_MISSES,
+        Hardware::BranchInstructions => PERF_COUNT_HW_BRANCH_INSTRUCTIONS,
+        Hardware::BranchMisses => PERF_COUNT_HW_BRANCH_MISSES,
+        Hardware::BusCycles => PERF_COUNT_HW_BUS_CYCLES,
+        Hardware::StalledCyclesFrontend => PERF_COUNT_HW_STALLED_CYCLES_FRONTEND,
+        Hardware::StalledCyclesBackend => PERF_COUNT_HW_STALLED_CYCLES_BACKEND,
+        Hardware::RefCPUCycles => PERF_COUNT_HW_REF_CPU_CYCLES,
+    }
+}
+
+fn software_config(event: Software) -> u64 {
+    match event {
+        Software::CpuClock => PERF_COUNT_SW_CPU_CLOCK,
+        Software::TaskClock => PERF_COUNT_SW_TASK_CLOCK,
+        Software::PageFaults => PERF_COUNT_SW_PAGE_FAULTS,
+        Software::ContextSwitches => PERF_COUNT_SW_CONTEXT_SWITCHES,
+        Software::CpuMigrations => PERF_COUNT_SW_CPU_MIGRATIONS,
+        Software::PageFaultsMin => PERF_COUNT_SW_PAGE_FAULTS_MIN,
+        Software::PageFaultsMaj => PERF_COUNT_SW_PAGE_FAULTS_MAJ,
+        Software::AlignmentFaults => PERF_COUNT_SW_ALIGNMENT_FAULTS,
+        Software::EmulationFaults => PERF_COUNT_SW_EMULATION_FAULTS,
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+const SYS_PERF_EVENT_OPEN: i64 = 298;
+#[cfg(target_arch = "aarch64")]
+const SYS_PERF_EVENT_OPEN: i64 = 241;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!(
+    "perfcnt-bench's raw perf_event_open bindings only know the syscall number for x86_64 and aarch64; add this target's number (see `man syscalls`) before building here"
+);
+
+/// Wraps `perf_event_open(2)`. `group_fd` is `-1` for a (new) group leader,
+/// or the leader's fd to join an existing group.
+pub fn perf_event_open(attr: &PerfEventAttr, pid: i32, group_fd: RawFd) -> io::Result<RawFd> {
+    let fd = unsafe {
+        libc::syscall(
+            SYS_PERF_EVENT_OPEN,
+            attr as *const PerfEventAttr,
+            pid,
+            -1i32, // cpu: any
+            group_fd,
+            0u64, // flags
+        )
+    };
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(fd as RawFd)
+    }
+}
+
+pub fn close(fd: RawFd) {
+    unsafe {
+        libc::close(fd);
+    }
+}
+
+/// `PERF_IOC_FLAG_GROUP`: apply the ioctl to the fd's whole group, not just
+/// the fd itself. For a counter that leads no group this is the same thing.
+const PERF_IOC_FLAG_GROUP: libc::c_ulong = 1;
+
+fn ioctl_group(fd: RawFd, request: u64) -> io::Result<()> {
+    let ret = unsafe { libc::ioctl(fd, request as libc::c_ulong, PERF_IOC_FLAG_GROUP) };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+const PERF_EVENT_IOC_RESET: u64 = 0x2403;
+const PERF_EVENT_IOC_ENABLE: u64 = 0x2400;
+const PERF_EVENT_IOC_DISABLE: u64 = 0x2401;
+
+pub fn reset(fd: RawFd) -> io::Result<()> {
+    ioctl_group(fd, PERF_EVENT_IOC_RESET)
+}
+
+pub fn enable(fd: RawFd) -> io::Result<()> {
+    ioctl_group(fd, PERF_EVENT_IOC_ENABLE)
+}
+
+pub fn disable(fd: RawFd) -> io::Result<()> {
+    ioctl_group(fd, PERF_EVENT_IOC_DISABLE)
+}
+
+fn read_u64s(fd: RawFd, count: usize) -> io::Result<Vec<u64>> {
+    let mut buf = vec![0u64; count];
+    let bytes = unsafe {
+        libc::read(
+            fd,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len() * mem::size_of::<u64>(),
+        )
+    };
+    if bytes < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(buf)
+}
+
+/// One counter's raw value plus the window it was actually scheduled for,
+/// straight from a `PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING`
+/// read. `time_running == 0` means the event never got PMU time at all.
+#[derive(Clone, Copy, Debug)]
+pub struct ScaledCount {
+    pub raw: u64,
+    pub time_enabled: u64,
+    pub time_running: u64,
+}
+
+impl ScaledCount {
+    /// The estimated full-window count, corrected for multiplexing, or
+    /// `None` when the event never ran (dividing by zero would lie).
+    pub fn scaled(&self) -> Option<f64> {
+        if self.time_running == 0 {
+            None
+        } else if self.time_running < self.time_enabled {
+            Some(self.raw as f64 * (self.time_enabled as f64 / self.time_running as f64))
+        } else {
+            Some(self.raw as f64)
+        }
+    }
+
+    /// Fraction of the measured window the event actually ran for; `1.0`
+    /// means it was never multiplexed away, `0.0` means it never ran.
+    pub fn confidence(&self) -> f64 {
+        if self.time_enabled == 0 {
+            0.0
+        } else {
+            self.time_running as f64 / self.time_enabled as f64
+        }
+    }
+}
+
+/// Reads a single, non-grouped counter opened with `DEFAULT_READ_FORMAT`.
+/// Buffer layout: `value`, `time_enabled`, `time_running`.
+pub fn read_scaled(fd: RawFd) -> io::Result<ScaledCount> {
+    let buf = read_u64s(fd, 3)?;
+    Ok(ScaledCount {
+        raw: buf[0],
+        time_enabled: buf[1],
+        time_running: buf[2],
+    })
+}
+
+/// Reads a `PERF_FORMAT_GROUP | DEFAULT_READ_FORMAT` buffer: `nr`, then the
+/// group's shared `time_enabled`/`time_running` (all members in a group are
+/// scheduled together, so there is one window for the whole group, not one
+/// per member), then `nr` value entries.
+pub fn read_group(fd: RawFd, member_count: usize) -> io::Result<(Vec<u64>, u64, u64)> {
+    let buf = read_u64s(fd, 3 + member_count)?;
+    let nr = (buf[0] as usize).min(member_count);
+    let time_enabled = buf[1];
+    let time_running = buf[2];
+    Ok((buf[3..3 + nr].to_vec(), time_enabled, time_running))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaled_count_full_window_is_exact() {
+        let count = ScaledCount {
+            raw: 42,
+            time_enabled: 100,
+            time_running: 100,
+        };
+        assert_eq!(count.scaled(), Some(42.0));
+        assert_eq!(count.confidence(), 1.0);
+    }
+
+    #[test]
+    fn scaled_count_partial_window_extrapolates() {
+        let count = ScaledCount {
+            raw: 50,
+            time_enabled: 100,
+            time_running: 50,
+        };
+        assert_eq!(count.scaled(), Some(100.0));
+        assert_eq!(count.confidence(), 0.5);
+    }
+
+    #[test]
+    fn scaled_count_never_ran_has_no_estimate() {
+        let count = ScaledCount {
+            raw: 0,
+            time_enabled: 100,
+            time_running: 0,
+        };
+        assert_eq!(count.scaled(), None);
+        assert_eq!(count.confidence(), 0.0);
+    }
+
+    #[test]
+    fn cache_config_packs_id_op_and_result() {
+        let config = cache_config(CacheId::LL, CacheOpId::Write, CacheOpResultId::Miss);
+        assert_eq!(config, 2 | (1 << 8) | (1 << 16));
+    }
+
+    #[test]
+    fn software_config_maps_each_variant_to_a_distinct_kernel_config() {
+        assert_eq!(software_config(Software::TaskClock), PERF_COUNT_SW_TASK_CLOCK);
+        assert_ne!(
+            software_config(Software::PageFaults),
+            software_config(Software::PageFaultsMin)
+        );
+    }
+}